@@ -12,6 +12,12 @@
 ///
 /// - `q=` :    your query
 ///  - `nhits`:  the number of hits that should be returned. (default to 10)
+///  - `max_chars`: length of the highlighted snippet returned per hit (default to 150)
+///  - `format`: `alfred` (default) for Alfred script-filter items, or `json`
+///    for raw `NamedFieldDocument`s with their BM25 score and doc address
+///  - `field`: restrict the default search fields to just this one (e.g. `title`)
+///  - `filter`: pin results to a file extension (`ext:pdf` or bare `pdf`) or a
+///    `loc` prefix (`loc:https://`)
 ///
 ///
 /// For instance, the following call should return the 20 most relevant
@@ -19,32 +25,66 @@
 ///
 ///     http://localhost:3000/api/?q=fulmicoton&nhits=20
 ///
+/// `POST /api/documents` accepts a bulk NDJSON body (optionally
+/// gzip/zstd-compressed) to add documents to the index without a full
+/// rebuild.
+///
+use iron::method::Method;
 use iron::mime::Mime;
 use iron::prelude::*;
 use iron::status;
 use iron::typemap::Key;
 use mount::Mount;
 use persistent::Read;
+use serde::Deserialize;
 use serde_derive::Serialize;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::From;
 use std::error::Error;
 use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tantivy::collector::{Count, TopDocs};
-use tantivy::query::QueryParser;
+use tantivy::doc;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, RegexQuery, TermQuery};
 use tantivy::schema::Field;
 use tantivy::schema::FieldType;
+use tantivy::schema::IndexRecordOption;
+use tantivy::schema::NamedFieldDocument;
 use tantivy::schema::Schema;
+use tantivy::tokenizer::TokenizerManager;
 use tantivy::Document;
 use tantivy::Index;
 use tantivy::IndexReader;
+use tantivy::IndexWriter;
+use tantivy::SnippetGenerator;
+use tantivy::Term;
 use urlencoded::UrlEncodedQuery;
 
+use crate::extension_of;
+
 #[derive(Serialize)]
 struct Results {
     items: Vec<AlfredItem>,
 }
 
+/// One line of a bulk-ingest NDJSON request body.
+#[derive(Deserialize)]
+struct IngestDocument {
+    title: String,
+    body: Option<String>,
+    loc: Option<String>,
+    archive_loc: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IngestSummary {
+    indexed: usize,
+}
+
 #[derive(Serialize)]
 struct AlfredAction {
     file: Option<String>,
@@ -54,54 +94,181 @@ struct AlfredAction {
 #[derive(Serialize)]
 struct AlfredItem {
     title: String,
+    subtitle: String,
     arg: String,
     action: AlfredAction,
 }
 
+/// Default length, in characters, of the highlighted `body` fragment
+/// returned alongside each hit.
+const DEFAULT_SNIPPET_CHARS: usize = 150;
+
+#[derive(Serialize)]
+struct NamedDocHit {
+    score: f32,
+    doc_address: String,
+    doc: NamedFieldDocument,
+}
+
+#[derive(Serialize)]
+struct JsonResults {
+    count: usize,
+    hits: Vec<NamedDocHit>,
+}
+
+/// The shape a `search` response is rendered in, selected by the `format`
+/// query-string parameter.
+enum SearchResponse {
+    Alfred(Results),
+    Json(JsonResults),
+}
+
+impl SearchResponse {
+    fn to_json(&self) -> String {
+        match self {
+            SearchResponse::Alfred(results) => serde_json::to_string_pretty(results).unwrap(),
+            SearchResponse::Json(results) => serde_json::to_string_pretty(results).unwrap(),
+        }
+    }
+}
+
+#[derive(Clone)]
 struct IndexServer {
     reader: IndexReader,
     query_parser: QueryParser,
     schema: Schema,
+    tokenizers: TokenizerManager,
+    writer: Arc<Mutex<IndexWriter>>,
+}
+
+/// Characters that need escaping so a `loc` prefix filter can be dropped
+/// verbatim into a regex pattern.
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Build a query for the `filter` query-string parameter, which pins
+/// results to either a file extension (`ext:pdf`) or a `loc` prefix
+/// (`loc:https://`). A bare value with no prefix is treated as an `ext`.
+fn build_filter_query(schema: &Schema, filter: &str) -> tantivy::Result<Box<dyn Query>> {
+    if let Some(prefix) = filter.strip_prefix("loc:") {
+        let loc = schema.get_field("loc").unwrap();
+        let pattern = format!("{}.*", escape_regex(prefix));
+        Ok(Box::new(RegexQuery::from_pattern(&pattern, loc)?))
+    } else {
+        let ext = schema.get_field("ext").unwrap();
+        let value = filter.strip_prefix("ext:").unwrap_or(filter).to_lowercase();
+        Ok(Box::new(TermQuery::new(
+            Term::from_field_text(ext, &value),
+            IndexRecordOption::Basic,
+        )))
+    }
+}
+
+/// The indexed text fields eligible to be searched by default — the same
+/// set `QueryParser` is built against in `load()`. A `field=` request
+/// parameter is only honored when it names one of these; anything else
+/// (like a `STORED`-only field such as `mtime`) would make `parse_query`
+/// fail, so callers must fall back to the full parser instead.
+fn indexed_text_fields(schema: &Schema) -> Vec<Field> {
+    schema
+        .fields()
+        .filter(|&(_, field_entry)| match field_entry.field_type() {
+            FieldType::Str(ref text_field_options) => {
+                text_field_options.get_indexing_options().is_some()
+            }
+            _ => false,
+        })
+        .map(|(field, _)| field)
+        .collect()
 }
 
 impl IndexServer {
     fn load(index: Index) -> tantivy::Result<IndexServer> {
         let schema = index.schema();
-        let default_fields: Vec<Field> = schema
-            .fields()
-            .filter(|&(_, field_entry)| match field_entry.field_type() {
-                FieldType::Str(ref text_field_options) => {
-                    text_field_options.get_indexing_options().is_some()
-                }
-                _ => false,
-            })
-            .map(|(field, _)| field)
-            .collect();
+        let default_fields = indexed_text_fields(&schema);
+        let tokenizers = index.tokenizers().clone();
         let query_parser =
-            QueryParser::new(schema.clone(), default_fields, index.tokenizers().clone());
+            QueryParser::new(schema.clone(), default_fields, tokenizers.clone());
         let reader = index.reader()?;
+        let writer = index.writer(50_000_000)?;
         Ok(IndexServer {
             reader,
             query_parser,
             schema,
+            tokenizers,
+            writer: Arc::new(Mutex::new(writer)),
         })
     }
 
-    fn search(&self, q: String, num_hits: usize, offset: usize) -> tantivy::Result<Results> {
-        let query = self
-            .query_parser
+    fn search(
+        &self,
+        q: String,
+        num_hits: usize,
+        offset: usize,
+        max_chars: usize,
+        format: &str,
+        field: Option<&str>,
+        filter: Option<&str>,
+    ) -> tantivy::Result<SearchResponse> {
+        let restricted_field = field.and_then(|name| {
+            let field = self.schema.get_field(name).ok()?;
+            indexed_text_fields(&self.schema)
+                .contains(&field)
+                .then_some(field)
+        });
+        let query_parser = match restricted_field {
+            Some(restricted) => {
+                QueryParser::new(self.schema.clone(), vec![restricted], self.tokenizers.clone())
+            }
+            None => self.query_parser.clone(),
+        };
+        let query = query_parser
             .parse_query(&q)
             .expect("Parsing the query failed");
+        let combined_query: Box<dyn Query> = match filter {
+            Some(filter) => Box::new(BooleanQuery::new(vec![
+                (Occur::Must, query.box_clone()),
+                (Occur::Must, build_filter_query(&self.schema, filter)?),
+            ])),
+            None => query.box_clone(),
+        };
         let searcher = self.reader.searcher();
-        let (top_docs, _) = {
+        let (top_docs, count) = {
             searcher.search(
-                &query,
+                &combined_query,
                 &(TopDocs::with_limit(num_hits).and_offset(offset), Count),
             )?
         };
+
+        if format == "json" {
+            let hits: Vec<NamedDocHit> = top_docs
+                .iter()
+                .map(|(score, doc_address)| {
+                    let doc: Document = searcher.doc(*doc_address).unwrap();
+                    NamedDocHit {
+                        score: *score,
+                        doc_address: format!("{:?}", doc_address),
+                        doc: self.schema.to_named_doc(&doc),
+                    }
+                })
+                .collect();
+            return Ok(SearchResponse::Json(JsonResults { count, hits }));
+        }
+
         let title = self.schema.get_field("title").unwrap();
         let loc = self.schema.get_field("loc").unwrap();
         let archive_loc = self.schema.get_field("archive_loc").unwrap();
+        let body = self.schema.get_field("body").unwrap();
+        let mut snippet_generator = SnippetGenerator::create(&searcher, &query, body)?;
+        snippet_generator.set_max_num_chars(max_chars);
         let items: Vec<AlfredItem> = {
             top_docs
                 .iter()
@@ -114,8 +281,10 @@ impl IndexServer {
                     } else {
                         aloc.to_owned()
                     };
+                    let subtitle = snippet_generator.snippet_for_document(&doc).to_html();
                     AlfredItem {
                         title: doc.get_first(title).unwrap().as_text().unwrap().to_owned(),
+                        subtitle,
                         action: AlfredAction {
                             file: if location.starts_with("/") {
                                 Some(location.clone())
@@ -133,7 +302,7 @@ impl IndexServer {
                 })
                 .collect()
         };
-        Ok(Results { items })
+        Ok(SearchResponse::Alfred(Results { items }))
     }
 }
 
@@ -181,8 +350,28 @@ fn search(req: &mut Request<'_, '_>) -> IronResult<Response> {
                 .get("offset")
                 .and_then(|offset_str| usize::from_str(&offset_str[0]).ok())
                 .unwrap_or(0);
-            let serp = index_server.search(query, num_hits, offset).unwrap();
-            let resp_json = serde_json::to_string_pretty(&serp).unwrap();
+            let max_chars: usize = qs_map
+                .get("max_chars")
+                .and_then(|max_chars_str| usize::from_str(&max_chars_str[0]).ok())
+                .unwrap_or(DEFAULT_SNIPPET_CHARS);
+            let format = qs_map
+                .get("format")
+                .map(|format_str| format_str[0].clone())
+                .unwrap_or_else(|| String::from("alfred"));
+            let field = qs_map.get("field").map(|field_str| field_str[0].clone());
+            let filter = qs_map.get("filter").map(|filter_str| filter_str[0].clone());
+            let serp = index_server
+                .search(
+                    query,
+                    num_hits,
+                    offset,
+                    max_chars,
+                    &format,
+                    field.as_deref(),
+                    filter.as_deref(),
+                )
+                .unwrap();
+            let resp_json = serp.to_json();
             let content_type = "application/json".parse::<Mime>().unwrap();
             Ok(Response::with((
                 content_type,
@@ -192,10 +381,157 @@ fn search(req: &mut Request<'_, '_>) -> IronResult<Response> {
         })
 }
 
+fn content_encoding(req: &Request<'_, '_>) -> String {
+    req.headers
+        .get_raw("content-encoding")
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Wrap the request body in a decompressing reader per its
+/// `Content-Encoding`, so a large (optionally compressed) NDJSON upload is
+/// streamed line-by-line instead of being fully buffered in memory.
+fn body_reader<'a>(req: &'a mut Request<'_, '_>, encoding: &str) -> Result<Box<dyn BufRead + 'a>, StringError> {
+    let body: &'a mut dyn std::io::Read = &mut req.body;
+    let reader: Box<dyn BufRead + 'a> = match encoding {
+        "gzip" => Box::new(BufReader::new(flate2::read::GzDecoder::new(body))),
+        "zstd" => Box::new(BufReader::new(zstd::stream::Decoder::new(body).map_err(
+            |e| StringError(format!("failed to open zstd stream: {}", e)),
+        )?)),
+        _ => Box::new(BufReader::new(body)),
+    };
+    Ok(reader)
+}
+
+/// Bulk-ingested documents have no filesystem path of their own, but the
+/// rest of the index relies on `path` as a stable document identity
+/// (`index_entries`/`update_index` key deletes off it) and on `ext` for
+/// `filter=ext:...`. Derive both from the document's content so re-posting
+/// the same line is idempotent and the doc still participates in
+/// extension filtering when it names a file-like `loc`/`archive_loc`.
+fn synthesize_path(doc: &IngestDocument) -> String {
+    let mut hasher = DefaultHasher::new();
+    doc.title.hash(&mut hasher);
+    doc.body.hash(&mut hasher);
+    doc.loc.hash(&mut hasher);
+    doc.archive_loc.hash(&mut hasher);
+    format!("bulk:{:x}", hasher.finish())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn to_document(schema: &Schema, doc: IngestDocument, path: &str) -> Document {
+    let title = schema.get_field("title").unwrap();
+    let body = schema.get_field("body").unwrap();
+    let loc = schema.get_field("loc").unwrap();
+    let archive_loc = schema.get_field("archive_loc").unwrap();
+    let path_field = schema.get_field("path").unwrap();
+    let mtime = schema.get_field("mtime").unwrap();
+    let ext = schema.get_field("ext").unwrap();
+    let ext_source = doc
+        .archive_loc
+        .as_deref()
+        .or(doc.loc.as_deref())
+        .unwrap_or("");
+    doc!(
+        title => doc.title,
+        body => doc.body.unwrap_or_default(),
+        loc => doc.loc.unwrap_or_default(),
+        archive_loc => doc.archive_loc.unwrap_or_default(),
+        path_field => path.to_string(),
+        mtime => now_secs(),
+        ext => extension_of(ext_source),
+    )
+}
+
+/// Parse and add every document in the NDJSON body, reading it
+/// incrementally rather than buffering the whole request. Stops at the
+/// first error so the caller can roll back whatever was added before it.
+/// Each document's synthesized `path` is deleted before it is re-added, so
+/// re-posting the same line replaces rather than duplicates it — the same
+/// pattern `index_entries`/`update_index` use in `lib.rs`.
+fn ingest_lines<R: BufRead>(
+    writer: &mut IndexWriter,
+    schema: &Schema,
+    reader: R,
+) -> Result<usize, IronError> {
+    let path_field = schema.get_field("path").unwrap();
+    let mut indexed = 0usize;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| {
+            IronError::new(
+                StringError(format!("failed to read line {}: {}", i + 1, e)),
+                status::BadRequest,
+            )
+        })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let doc: IngestDocument = serde_json::from_str(&line).map_err(|e| {
+            IronError::new(
+                StringError(format!("invalid document on line {}: {}", i + 1, e)),
+                status::BadRequest,
+            )
+        })?;
+        let path = synthesize_path(&doc);
+        writer.delete_term(Term::from_field_text(path_field, &path));
+        writer
+            .add_document(to_document(schema, doc, &path))
+            .map_err(|e| IronError::new(StringError(format!("{}", e)), status::InternalServerError))?;
+        indexed += 1;
+    }
+    Ok(indexed)
+}
+
+/// `POST /api/documents` — bulk-ingest an NDJSON body of `{title, body,
+/// loc, archive_loc}` objects, optionally gzip/zstd compressed, committing
+/// once the whole body has been consumed. If any line fails to parse or
+/// index, the whole batch is rolled back so a partial failure can't leak
+/// uncommitted writes into a later, unrelated commit.
+fn ingest_documents(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    if req.method != Method::Post {
+        return Err(IronError::new(
+            StringError(String::from("only POST is supported")),
+            status::MethodNotAllowed,
+        ));
+    }
+
+    let (writer_handle, schema) = {
+        let index_server = req.get::<Read<IndexServer>>().unwrap();
+        (index_server.writer.clone(), index_server.schema.clone())
+    };
+    let encoding = content_encoding(req);
+    let reader =
+        body_reader(req, &encoding).map_err(|e| IronError::new(e, status::BadRequest))?;
+
+    let mut writer = writer_handle.lock().unwrap();
+    let indexed = match ingest_lines(&mut writer, &schema, reader) {
+        Ok(indexed) => indexed,
+        Err(e) => {
+            let _ = writer.rollback();
+            return Err(e);
+        }
+    };
+    writer
+        .commit()
+        .map_err(|e| IronError::new(StringError(format!("{}", e)), status::InternalServerError))?;
+
+    let resp_json = serde_json::to_string_pretty(&IngestSummary { indexed }).unwrap();
+    let content_type = "application/json".parse::<Mime>().unwrap();
+    Ok(Response::with((content_type, status::Ok, resp_json)))
+}
+
 pub fn serve(index: Index, host: &str) -> tantivy::Result<()> {
     let mut mount = Mount::new();
     let server = IndexServer::load(index)?;
 
+    mount.mount("/api/documents", ingest_documents);
     mount.mount("/api", search);
 
     let mut middleware = Chain::new(mount);
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::default::Default;
 use std::fs::File;
 use std::io::Read;
@@ -5,20 +6,27 @@ use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc::sync_channel;
 use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use anyhow::Result;
 use ignore::{WalkBuilder, WalkState};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::Deserialize;
-use tantivy::{directory::MmapDirectory, doc, schema::*, Index};
+use tantivy::{directory::MmapDirectory, doc, schema::*, Document, Index, IndexWriter, Term};
 
 pub mod api;
 
+/// How long to wait for a path to stop changing before re-indexing it.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
 #[derive(Debug)]
 pub struct Entry {
     pub title: String,
     pub body: Option<String>,
     pub loc: Option<String>,
     pub archive_loc: Option<String>,
+    pub path: String,
+    pub mtime: u64,
 }
 
 #[derive(Deserialize)]
@@ -36,6 +44,8 @@ fn handle_webloc(path: String) -> Result<Option<Entry>> {
         loc: Some(webloc.url),
         body: None,
         archive_loc: None,
+        path,
+        mtime: 0,
     }))
 }
 
@@ -48,7 +58,9 @@ fn handle_text(path: String) -> Result<Option<Entry>> {
         title: filename(&p),
         loc: None,
         body: Some(contents),
-        archive_loc: Some(path),
+        archive_loc: Some(path.clone()),
+        path,
+        mtime: 0,
     }))
 }
 
@@ -76,55 +88,215 @@ fn handle_pdf(path: String) -> Result<Option<Entry>> {
         title: filename(&p),
         loc: None,
         body: Some(contents),
-        archive_loc: Some(path),
+        archive_loc: Some(path.clone()),
+        path,
+        mtime: 0,
     }))
 }
 
-/// Digest the file at the provided path, producing an entry
-/// when possible.
-fn digest(path: String) -> Result<Option<Entry>> {
-    if let Some((_, extension)) = path.rsplit_once(".") {
+fn mtime_secs(path: &str) -> Result<u64> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn handle_csv(path: String, title_column: Option<&str>) -> Result<Vec<Entry>> {
+    let mut reader = csv::Reader::from_path(&path)?;
+    let title_idx = title_column
+        .and_then(|name| reader.headers().ok().and_then(|h| h.iter().position(|c| c == name)))
+        .unwrap_or(0);
+    let mut entries = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let title = record
+            .get(title_idx)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| format!("{} #{}", filename(&Path::new(&path)), i));
+        let body = record
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != title_idx)
+            .map(|(_, field)| field.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        entries.push(Entry {
+            title,
+            body: Some(body),
+            loc: None,
+            archive_loc: Some(path.clone()),
+            path: path.clone(),
+            mtime: 0,
+        });
+    }
+    Ok(entries)
+}
+
+/// Turn one parsed JSON object into an `Entry`. A `title` field becomes the
+/// entry's title (falling back to `<file stem> #<index>`), a `url`/`loc`
+/// field maps to `loc`, and everything else is flattened into `body`.
+fn record_to_entry(path: &str, index: usize, value: &serde_json::Value) -> Option<Entry> {
+    let obj = value.as_object()?;
+    let mut title = None;
+    let mut loc = None;
+    let mut body_parts = Vec::new();
+    for (key, val) in obj {
+        let text = match val {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        match key.as_str() {
+            "title" => title = Some(text),
+            "url" | "loc" => loc = Some(text),
+            _ => body_parts.push(format!("{}: {}", key, text)),
+        }
+    }
+    Some(Entry {
+        title: title.unwrap_or_else(|| format!("{} #{}", filename(&Path::new(path)), index)),
+        body: Some(body_parts.join("\n")),
+        loc,
+        archive_loc: Some(path.to_string()),
+        path: path.to_string(),
+        mtime: 0,
+    })
+}
+
+fn handle_ndjson(path: String) -> Result<Vec<Entry>> {
+    let mut contents = String::new();
+    File::open(&path)?.read_to_string(&mut contents)?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .filter_map(|(i, line)| {
+            serde_json::from_str::<serde_json::Value>(line)
+                .ok()
+                .and_then(|value| record_to_entry(&path, i, &value))
+        })
+        .collect())
+}
+
+fn handle_json(path: String) -> Result<Vec<Entry>> {
+    let mut contents = String::new();
+    File::open(&path)?.read_to_string(&mut contents)?;
+    let value: serde_json::Value = serde_json::from_str(&contents)?;
+    Ok(value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(i, v)| record_to_entry(&path, i, v))
+        .collect())
+}
+
+/// Digest the file at the provided path, producing the entries it contains.
+/// Most formats yield at most one entry, but structured record formats
+/// (CSV, JSON, NDJSON) yield one entry per row/object.
+fn digest(path: String, title_column: Option<&str>) -> Result<Vec<Entry>> {
+    let mtime = mtime_secs(&path)?;
+    let entries = if let Some((_, extension)) = path.rsplit_once(".") {
         match extension {
-            "pdf" => handle_pdf(path),
-            "txt" | "markdown" | "md" => handle_text(path),
-            "webloc" => handle_webloc(path),
-            _ => Ok(None),
+            "pdf" => handle_pdf(path.clone())?.into_iter().collect(),
+            "txt" | "markdown" | "md" => handle_text(path.clone())?.into_iter().collect(),
+            "webloc" => handle_webloc(path.clone())?.into_iter().collect(),
+            "csv" => handle_csv(path.clone(), title_column)?,
+            "json" => handle_json(path.clone())?,
+            "jsonl" | "ndjson" => handle_ndjson(path.clone())?,
+            _ => Vec::new(),
         }
     } else {
-        Ok(Some(Entry {
+        vec![Entry {
             title: filename(&Path::new(&path)),
-            archive_loc: Some(path),
+            archive_loc: Some(path.clone()),
             body: None,
             loc: None,
-        }))
-    }
+            path: path.clone(),
+            mtime,
+        }]
+    };
+    Ok(entries
+        .into_iter()
+        .map(|mut entry| {
+            entry.path = path.clone();
+            entry.mtime = mtime;
+            entry
+        })
+        .collect())
 }
 
-pub fn build_index(src_path: String, db_path: Option<String>, threads: usize) -> Result<Index> {
+fn build_schema() -> Schema {
     let mut schema_builder = Schema::builder();
-    let title = schema_builder.add_text_field("title", TEXT | STORED);
-    let body = schema_builder.add_text_field("body", TEXT);
-    let loc = schema_builder.add_text_field("loc", STRING | STORED);
-    let archive_loc = schema_builder.add_text_field("archive_loc", STRING | STORED);
-    let schema = schema_builder.build();
+    schema_builder.add_text_field("title", TEXT | STORED);
+    schema_builder.add_text_field("body", TEXT | STORED);
+    schema_builder.add_text_field("loc", STRING | STORED);
+    schema_builder.add_text_field("archive_loc", STRING | STORED);
+    schema_builder.add_text_field("path", STRING | STORED);
+    schema_builder.add_u64_field("mtime", STORED);
+    schema_builder.add_text_field("ext", STRING | STORED);
+    schema_builder.build()
+}
+
+/// The lowercased file extension of `path`, or an empty string if it has
+/// none. Used to populate the `ext` field so searches can be scoped to a
+/// document kind ("only PDFs").
+pub(crate) fn extension_of(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .unwrap_or_default()
+}
+
+fn entry_to_doc(schema: &Schema, entry: Entry) -> Document {
+    let title = schema.get_field("title").unwrap();
+    let body = schema.get_field("body").unwrap();
+    let loc = schema.get_field("loc").unwrap();
+    let archive_loc = schema.get_field("archive_loc").unwrap();
+    let path = schema.get_field("path").unwrap();
+    let mtime = schema.get_field("mtime").unwrap();
+    let ext = schema.get_field("ext").unwrap();
+    doc!(
+        title => entry.title,
+        body => entry.body.unwrap_or("".to_string()),
+        loc => entry.loc.unwrap_or("".to_string()),
+        archive_loc => entry.archive_loc.unwrap_or("".to_string()),
+        path => entry.path.clone(),
+        mtime => entry.mtime,
+        ext => extension_of(&entry.path),
+    )
+}
+
+/// Replace any existing documents sharing `entries`' path with a fresh
+/// copy, so re-ingesting a path updates it in place instead of duplicating
+/// it. All entries passed in a single call must share the same `path`.
+fn index_entries(index_writer: &mut IndexWriter, schema: &Schema, entries: Vec<Entry>) -> Result<()> {
+    if let Some(path) = entries.first().map(|entry| entry.path.clone()) {
+        let path_field = schema.get_field("path").unwrap();
+        index_writer.delete_term(Term::from_field_text(path_field, &path));
+    }
+    for entry in entries {
+        index_writer.add_document(entry_to_doc(schema, entry))?;
+    }
+    Ok(())
+}
+
+pub fn build_index(
+    src_path: String,
+    db_path: Option<String>,
+    threads: usize,
+    title_column: Option<String>,
+) -> Result<Index> {
+    let schema = build_schema();
     let index = match db_path {
         Some(path) => Index::open_or_create(MmapDirectory::open(path)?, schema)?,
         None => Index::create_in_ram(schema),
     };
 
-    let (tx, rx) = sync_channel::<Entry>(threads);
+    let (tx, rx) = sync_channel::<Vec<Entry>>(threads);
 
     let mut index_writer = index.writer_with_num_threads(threads, 50_000_000).unwrap();
+    let writer_schema = index.schema();
     let writer = thread::spawn(move || {
-        for entry in rx.iter() {
-            index_writer
-                .add_document(doc!(
-                    title => entry.title,
-                    body => entry.body.unwrap_or("".to_string()),
-                    loc => entry.loc.unwrap_or("".to_string()),
-                    archive_loc => entry.archive_loc.unwrap_or("".to_string()),
-                ))
-                .expect("failed to insert doc");
+        for entries in rx.iter() {
+            index_entries(&mut index_writer, &writer_schema, entries).expect("failed to insert doc");
         }
         index_writer.commit().expect("failed to commit");
     });
@@ -136,15 +308,16 @@ pub fn build_index(src_path: String, db_path: Option<String>, threads: usize) ->
 
     walker.run(|| {
         let tx = tx.clone();
+        let title_column = title_column.clone();
         Box::new(move |entry_o| match entry_o {
             Ok(de) => {
                 if de.path().is_file() {
                     let path = de.into_path().into_os_string().into_string().unwrap();
-                    match digest(path) {
-                        Ok(Some(entry)) => {
-                            tx.send(entry).unwrap();
+                    match digest(path, title_column.as_deref()) {
+                        Ok(entries) if !entries.is_empty() => {
+                            tx.send(entries).unwrap();
                         }
-                        Ok(None) => {}
+                        Ok(_) => {}
                         Err(e) => eprintln!("{}", e),
                     };
                 }
@@ -160,3 +333,78 @@ pub fn build_index(src_path: String, db_path: Option<String>, threads: usize) ->
     writer.join().unwrap();
     Ok(index)
 }
+
+/// Re-ingest a single path, replacing its existing document(s) (if any).
+/// When the path no longer exists on disk, its document is removed instead.
+pub fn update_index(
+    index_writer: &mut IndexWriter,
+    schema: &Schema,
+    path: &str,
+    title_column: Option<&str>,
+) -> Result<()> {
+    let path_field = schema.get_field("path").unwrap();
+    index_writer.delete_term(Term::from_field_text(path_field, path));
+    if Path::new(path).is_file() {
+        for entry in digest(path.to_string(), title_column)? {
+            index_writer.add_document(entry_to_doc(schema, entry))?;
+        }
+    }
+    Ok(())
+}
+
+/// Watch `src_path` for changes and keep `index` in sync with the
+/// filesystem, debouncing bursts of events for the same path so a save
+/// (which often fires several create/modify events in a row) only
+/// triggers a single re-ingest.
+pub fn watch(
+    index: Index,
+    src_path: String,
+    title_column: Option<String>,
+    threads: usize,
+) -> Result<()> {
+    let schema = index.schema();
+    let mut index_writer = index.writer_with_num_threads(threads, 50_000_000)?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher: RecommendedWatcher = notify::recommended_watcher(move |res| {
+        if let Ok(event) = res {
+            let _ = tx.send(event);
+        }
+    })?;
+    watcher.watch(Path::new(&src_path), RecursiveMode::Recursive)?;
+
+    println!("watching {} for changes", src_path);
+    let mut pending: HashMap<String, Instant> = HashMap::new();
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(event) => {
+                let event: notify::Event = event;
+                for changed in event.paths {
+                    if let Some(changed) = changed.to_str() {
+                        pending.insert(changed.to_string(), Instant::now());
+                    }
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let ready: Vec<String> = pending
+            .iter()
+            .filter(|(_, seen)| seen.elapsed() >= DEBOUNCE)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        if ready.is_empty() {
+            continue;
+        }
+        for path in ready {
+            pending.remove(&path);
+            if let Err(e) = update_index(&mut index_writer, &schema, &path, title_column.as_deref()) {
+                eprintln!("failed to update index for {}: {}", path, e);
+            }
+        }
+        index_writer.commit()?;
+    }
+    Ok(())
+}
@@ -26,14 +26,35 @@ struct Args {
     /// Persist the index to the following directory. If not supplied the index will remain in RAM
     #[structopt(short = "d", long = "dir", default_value = "3000")]
     port: u16,
+    /// After the initial build, keep watching the source directory and
+    /// incrementally re-index changed files instead of serving.
+    #[structopt(short = "w", long = "watch")]
+    watch: bool,
+    /// Column to use as the title when ingesting CSV files. Defaults to the
+    /// first column.
+    #[structopt(long = "title-column")]
+    title_column: Option<String>,
 }
 
 #[paw::main]
 fn main(args: Args) -> Result<()> {
     let now = Instant::now();
-    let index = build_index(args.src, args.dest, args.threads as usize)?;
+    let src = args.src.clone();
+    let title_column = args.title_column.clone();
+    let index = build_index(
+        args.src,
+        args.dest,
+        args.threads as usize,
+        args.title_column,
+    )?;
     let elapsed_time = now.elapsed();
     println!("Build index in {} seconds.", elapsed_time.as_secs());
+
+    if args.watch {
+        return memex::watch(index, src, title_column, args.threads as usize)
+            .context("Failed to watch source directory");
+    }
+
     let host = format!("{}:{}", args.host, args.port);
     api::serve(index, &host).context("Failed to serve index")
 }